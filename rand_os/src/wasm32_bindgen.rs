@@ -3,98 +3,134 @@ use super::OsRngImpl;
 
 use wasm_bindgen::prelude::*;
 
+// WASI exposes a `random_get` syscall directly, with no JS engine or
+// `this`/`crypto` global involved, so it's declared as a plain `extern "C"`
+// import rather than through `wasm_bindgen`.
+#[cfg(target_os = "wasi")]
+mod wasi_unstable {
+    #[link(wasm_import_module = "wasi_snapshot_preview1")]
+    extern "C" {
+        pub fn random_get(buf: *mut u8, buf_len: usize) -> u16;
+    }
+}
+
 #[wasm_bindgen]
 extern "C" {
+    #[derive(Clone, Debug)]
     pub type Function;
     #[wasm_bindgen(constructor)]
     pub fn new(s: &str) -> Function;
     #[wasm_bindgen(method)]
     pub fn call(this: &Function, self_: &JsValue) -> JsValue;
+    // Once a `getRandomValues`/`randomFillSync` `Function` has been resolved
+    // off of its owning object, it can be invoked directly through
+    // `Function.prototype.call` with that object passed back in as the
+    // receiver, with no further property lookup.
+    #[wasm_bindgen(method, js_name = call)]
+    pub fn call_with_buf(this: &Function, self_: &JsValue, buf: &mut [u8]);
 
     pub type This;
-    #[wasm_bindgen(method, getter, structural, js_name = self)]
-    pub fn self_(me: &This) -> JsValue;
     #[wasm_bindgen(method, getter, structural)]
     pub fn crypto(me: &This) -> JsValue;
 
     #[derive(Clone, Debug)]
     pub type BrowserCrypto;
 
-    // TODO: these `structural` annotations here ideally wouldn't be here to
-    // avoid a JS shim, but for now with feature detection they're
-    // unavoidable.
+    // This `structural` getter is only ever used once, in `OsRngImpl::new`,
+    // to resolve and cache the `getRandomValues` `Function`; `fill_chunk`
+    // then calls the cached `Function` directly, so the `structural` JS shim
+    // this getter needs isn't paid on every fill.
     #[wasm_bindgen(method, js_name = getRandomValues, structural, getter)]
     pub fn get_random_values_fn(me: &BrowserCrypto) -> JsValue;
-    #[wasm_bindgen(method, js_name = getRandomValues, structural)]
-    pub fn get_random_values(me: &BrowserCrypto, buf: &mut [u8]);
 
-    #[wasm_bindgen(js_name = require)]
-    pub fn node_require(s: &str) -> NodeCrypto;
+    // `require` throws if called where there's no CommonJS `require` (the
+    // browser, or an ESM-only context), so this is `catch`-wrapped to turn
+    // that throw into a `Result` instead of an uncaught JS exception.
+    #[wasm_bindgen(catch, js_name = require)]
+    pub fn node_require(s: &str) -> Result<NodeCrypto, JsValue>;
 
     #[derive(Clone, Debug)]
     pub type NodeCrypto;
 
-    #[wasm_bindgen(method, js_name = randomFillSync, structural)]
-    pub fn random_fill_sync(me: &NodeCrypto, buf: &mut [u8]);
+    // See the comment on `get_random_values_fn`: resolved once in `new` and
+    // cached, rather than looked up on every `fill_chunk`.
+    #[wasm_bindgen(method, js_name = randomFillSync, structural, getter)]
+    pub fn random_fill_sync_fn(me: &NodeCrypto) -> JsValue;
 }
 
 #[derive(Clone, Debug)]
 pub enum OsRng {
-    Node(NodeCrypto),
-    Browser(BrowserCrypto),
+    Node(NodeCrypto, Function),
+    Browser(BrowserCrypto, Function),
+    #[cfg(target_os = "wasi")]
+    Wasi,
 }
 
 impl OsRngImpl for OsRng {
+    #[cfg(target_os = "wasi")]
     fn new() -> Result<OsRng, Error> {
-        // First up we need to detect if we're running in node.js or a
-        // browser. To do this we get ahold of the `this` object (in a bit
-        // of a roundabout fashion).
-        //
-        // Once we have `this` we look at its `self` property, which is
-        // only defined on the web (either a main window or web worker).
+        // Standalone wasm runtimes (Wasmtime, Wasmer, ...) implement WASI but
+        // have no JS engine underneath, so there's no `this`/`crypto` global
+        // to probe for; go straight to the `random_get` syscall.
+        Ok(OsRng::Wasi)
+    }
+
+    #[cfg(not(target_os = "wasi"))]
+    fn new() -> Result<OsRng, Error> {
+        // First up we need to get ahold of the global object, which we do in
+        // a bit of a roundabout fashion.
         let this = Function::new("return this").call(&JsValue::undefined());
         assert!(this != JsValue::undefined());
         let this = This::from(this);
-        let is_browser = this.self_() != JsValue::undefined();
 
-        if !is_browser {
-            return Ok(OsRng::Node(node_require("crypto")))
-        }
-
-        // If `self` is defined then we're in a browser somehow (main window
-        // or web worker). Here we want to try to use
-        // `crypto.getRandomValues`, but if `crypto` isn't defined we assume
-        // we're in an older web browser and the OS RNG isn't available.
+        // `crypto.getRandomValues` used to be a browser-only API, but it's
+        // now also exposed on `globalThis` by recent Node.js and Deno, so
+        // probe for it before assuming we're in classic Node.
         let crypto = this.crypto();
-        if crypto.is_undefined() {
-            let msg = "self.crypto is undefined";
-            return Err(Error::new(ErrorKind::Unavailable, msg))
+        if !crypto.is_undefined() {
+            let crypto: BrowserCrypto = crypto.into();
+            let get_random_values = crypto.get_random_values_fn();
+            if !get_random_values.is_undefined() {
+                return Ok(OsRng::Browser(crypto, get_random_values.into()))
+            }
         }
 
-        // Test if `crypto.getRandomValues` is undefined as well
-        let crypto: BrowserCrypto = crypto.into();
-        if crypto.get_random_values_fn().is_undefined() {
-            let msg = "crypto.getRandomValues is undefined";
-            return Err(Error::new(ErrorKind::Unavailable, msg))
+        // No global `crypto.getRandomValues`; fall back to classic Node's
+        // `require("crypto")`. This throws in the browser and in ESM-only /
+        // no-`require` contexts, so a throw here is reported as a clean
+        // error rather than propagating as an uncaught JS exception.
+        match node_require("crypto") {
+            Ok(node) => {
+                let random_fill_sync = node.random_fill_sync_fn();
+                Ok(OsRng::Node(node, random_fill_sync.into()))
+            }
+            Err(_) => {
+                let msg = "self.crypto and require(\"crypto\") are both unavailable";
+                Err(Error::new(ErrorKind::Unavailable, msg))
+            }
         }
-
-        // Ok! `self.crypto.getRandomValues` is a defined value, so let's
-        // assume we can do browser crypto.
-        Ok(OsRng::Browser(crypto))
     }
 
     fn fill_chunk(&mut self, dest: &mut [u8]) -> Result<(), Error> {
         match *self {
-            OsRng::Node(ref n) => n.random_fill_sync(dest),
-            OsRng::Browser(ref n) => n.get_random_values(dest),
+            OsRng::Node(ref n, ref f) => f.call_with_buf(n.as_ref(), dest),
+            OsRng::Browser(ref n, ref f) => f.call_with_buf(n.as_ref(), dest),
+            #[cfg(target_os = "wasi")]
+            OsRng::Wasi => {
+                let ret = unsafe { wasi_unstable::random_get(dest.as_mut_ptr(), dest.len()) };
+                if ret != 0 {
+                    let msg = "wasi::random_get failed";
+                    return Err(Error::new(ErrorKind::Unavailable, msg));
+                }
+            }
         }
         Ok(())
     }
 
     fn max_chunk_size(&self) -> usize {
         match *self {
-            OsRng::Node(_) => usize::max_value(),
-            OsRng::Browser(_) => {
+            OsRng::Node(..) => usize::max_value(),
+            OsRng::Browser(..) => {
                 // see https://developer.mozilla.org/en-US/docs/Web/API/Crypto/getRandomValues
                 //
                 // where it says:
@@ -103,13 +139,17 @@ impl OsRngImpl for OsRng {
                 // > requested length is greater than 65536 bytes.
                 65536
             }
+            #[cfg(target_os = "wasi")]
+            OsRng::Wasi => usize::max_value(),
         }
     }
 
     fn method_str(&self) -> &'static str {
         match *self {
-            OsRng::Node(_) => "crypto.randomFillSync",
-            OsRng::Browser(_) => "crypto.getRandomValues",
+            OsRng::Node(..) => "crypto.randomFillSync",
+            OsRng::Browser(..) => "crypto.getRandomValues",
+            #[cfg(target_os = "wasi")]
+            OsRng::Wasi => "wasi::random_get",
         }
     }
-}
\ No newline at end of file
+}